@@ -0,0 +1,42 @@
+//! Batched read helpers for the TUN hot loop.
+//!
+//! The read path normally costs one syscall per packet, capping throughput
+//! at one wakeup per frame. [`try_read_ready`] lets the handler
+//! opportunistically keep pulling in already-buffered frames off the device
+//! without waiting for a fresh wakeup.
+//!
+//! There is deliberately no equivalent write-side batching helper: a TUN
+//! device is packet-oriented, so one `write()` must carry exactly one IP
+//! datagram. Coalescing several distinct return packets into one `writev`
+//! either concatenates them into a single malformed frame (on a device
+//! that actually honors vectored writes) or silently writes only the first
+//! buffer while claiming success (tokio's default `AsyncWrite::write_vectored`
+//! falls back to the first `IoSlice`); and pre-collecting several return
+//! packets only to still `device.write()` them one at a time buys nothing.
+//! Each return packet is written with its own `device.write(&packet)` call
+//! as soon as it's available instead.
+
+use std::future::poll_fn;
+use std::io::Result;
+use std::pin::Pin;
+use std::task::Poll;
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Reads one frame from `device` into `buf` without awaiting a fresh
+/// wakeup. Returns `Ok(Some(n))` for a frame of length `n`, `Ok(None)` if
+/// nothing is buffered right now, or `Err` on a real I/O error.
+pub async fn try_read_ready<D>(device: &mut D, buf: &mut [u8]) -> Result<Option<usize>>
+where
+    D: AsyncRead + Unpin,
+{
+    poll_fn(|cx| {
+        let mut read_buf = ReadBuf::new(buf);
+        match Pin::new(&mut *device).poll_read(cx, &mut read_buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(Some(read_buf.filled().len()))),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Ready(Ok(None)),
+        }
+    })
+    .await
+}
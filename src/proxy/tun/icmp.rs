@@ -0,0 +1,375 @@
+//! Real ICMP echo (ping) proxying through outbound sockets.
+//!
+//! smoltcp's `Interface` answers every `EchoRequest` locally, so pinging a
+//! remote address through the tunnel previously only measured the stack's
+//! own turnaround time rather than reachability of the real destination.
+//! Each echo request is instead forwarded to the real destination over a
+//! raw ICMP socket bound/steered using the proxy's `ConnectOpts` (the same
+//! outbound-egress configuration — bind address, bind interface — that
+//! `TcpTun`/`UdpTun` apply to proxied connections), and the reply is
+//! rebuilt as an IP+ICMP packet and pushed back through the TUN device.
+//! There is no per-probe association table: each probe is a one-shot
+//! fire-and-forget `spawn_blocking` task matched back to its caller purely
+//! by `(ident, seq_no, peer address)`, not by a tracked table entry.
+//! Non-echo ICMP traffic is left to smoltcp, which still needs to drive
+//! path-mtu/port-unreachable handling for the interface.
+//!
+//! Note this forwards the *probe*, not the tunnel's wire protocol: most
+//! proxy protocols (including this one) only relay TCP/UDP payloads, so
+//! ICMP can't literally be carried through the remote proxy server itself.
+//! "Through the proxy" here means the probe leaves the host via the same
+//! egress path the proxy's own outbound connections use, rather than
+//! whatever route the kernel would pick by default.
+//!
+//! Raw ICMP sockets require `CAP_NET_RAW` (or running as root) on most
+//! platforms; `probe_v4`/`probe_v6` surface the resulting `EPERM` as a
+//! regular probe failure (logged, reply simply never arrives) rather than
+//! panicking or silently hanging.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    Icmpv4Packet, Icmpv4Repr, Icmpv6Packet, Icmpv6Repr, IpAddress, Ipv4Packet, Ipv4Repr,
+    Ipv6Packet, Ipv6Repr,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::sync::mpsc;
+
+use crate::app::Context;
+use crate::transport::raw::ConnectOpts;
+
+/// How long we wait for a real echo reply before giving up on a probe.
+const ECHO_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Forwards ICMPv4/ICMPv6 echo requests to their real destination and
+/// rebuilds the reply for delivery back through the TUN device.
+pub struct IcmpTun {
+    /// Outbound egress configuration taken from the proxy `Context`, applied
+    /// to every probe socket so pings leave via the same path as proxied
+    /// TCP/UDP traffic instead of the kernel's default route.
+    connect_opts: ConnectOpts,
+    reply_tx: mpsc::Sender<Vec<u8>>,
+    reply_rx: mpsc::Receiver<Vec<u8>>,
+}
+
+impl IcmpTun {
+    pub fn new(context: Context) -> IcmpTun {
+        let (reply_tx, reply_rx) = mpsc::channel(64);
+        IcmpTun {
+            connect_opts: context.connect_opts_ref().clone(),
+            reply_tx,
+            reply_rx,
+        }
+    }
+
+    /// Handles one ICMPv4 packet read from the TUN device. Returns `Ok(true)`
+    /// if it was an echo request, in which case the caller must *not* also
+    /// drive it through smoltcp's interface, or the request would be
+    /// answered twice (once by us, once locally by smoltcp). A background
+    /// probe to the real destination is spawned; the reply arrives later via
+    /// `recv_packet`.
+    pub async fn handle_icmpv4_packet(
+        &mut self,
+        src_addr: Ipv4Addr,
+        dst_addr: Ipv4Addr,
+        packet: &Icmpv4Packet<&[u8]>,
+    ) -> Result<bool> {
+        let repr = Icmpv4Repr::parse(packet, &ChecksumCapabilities::default())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+
+        let (ident, seq_no, data) = match repr {
+            Icmpv4Repr::EchoRequest { ident, seq_no, data } => (ident, seq_no, data),
+            // Only echo requests are proxied; everything else is left for
+            // smoltcp's interface to handle as before.
+            _ => return Ok(false),
+        };
+
+        log::trace!(
+            "[TUN] ICMPv4 echo request {} -> {} id={} seq={}",
+            src_addr,
+            dst_addr,
+            ident,
+            seq_no
+        );
+
+        let data = data.to_vec();
+        let reply_tx = self.reply_tx.clone();
+        let connect_opts = self.connect_opts.clone();
+
+        tokio::spawn(async move {
+            match probe_v4(&connect_opts, dst_addr, ident, seq_no, &data).await {
+                Ok(reply_data) => {
+                    let packet = build_icmpv4_reply(dst_addr, src_addr, ident, seq_no, &reply_data);
+                    if reply_tx.send(packet).await.is_err() {
+                        log::debug!("[TUN] dropping ICMPv4 echo reply, tun handler gone");
+                    }
+                }
+                Err(err) => {
+                    log::debug!("[TUN] ICMPv4 echo probe to {} failed, error: {}", dst_addr, err);
+                }
+            }
+        });
+
+        Ok(true)
+    }
+
+    /// Handles one ICMPv6 packet read from the TUN device; see
+    /// [`handle_icmpv4_packet`](Self::handle_icmpv4_packet) for the echo
+    /// semantics and return value.
+    pub async fn handle_icmpv6_packet(
+        &mut self,
+        src_addr: Ipv6Addr,
+        dst_addr: Ipv6Addr,
+        packet: &Icmpv6Packet<&[u8]>,
+    ) -> Result<bool> {
+        let repr = Icmpv6Repr::parse(
+            &IpAddress::Ipv6(src_addr),
+            &IpAddress::Ipv6(dst_addr),
+            packet,
+            &ChecksumCapabilities::default(),
+        )
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+
+        let (ident, seq_no, data) = match repr {
+            Icmpv6Repr::EchoRequest { ident, seq_no, data } => (ident, seq_no, data),
+            _ => return Ok(false),
+        };
+
+        log::trace!(
+            "[TUN] ICMPv6 echo request {} -> {} id={} seq={}",
+            src_addr,
+            dst_addr,
+            ident,
+            seq_no
+        );
+
+        let data = data.to_vec();
+        let reply_tx = self.reply_tx.clone();
+        let connect_opts = self.connect_opts.clone();
+
+        tokio::spawn(async move {
+            match probe_v6(&connect_opts, dst_addr, ident, seq_no, &data).await {
+                Ok(reply_data) => {
+                    let packet = build_icmpv6_reply(dst_addr, src_addr, ident, seq_no, &reply_data);
+                    if reply_tx.send(packet).await.is_err() {
+                        log::debug!("[TUN] dropping ICMPv6 echo reply, tun handler gone");
+                    }
+                }
+                Err(err) => {
+                    log::debug!("[TUN] ICMPv6 echo probe to {} failed, error: {}", dst_addr, err);
+                }
+            }
+        });
+
+        Ok(true)
+    }
+
+    /// Awaits the next ICMP echo reply ready to be written back to the TUN
+    /// device, mirroring `TcpTun::recv_packet`/`UdpTun::recv_packet`.
+    pub async fn recv_packet(&mut self) -> Vec<u8> {
+        match self.reply_rx.recv().await {
+            Some(packet) => packet,
+            None => unreachable!("IcmpTun owns both ends of reply_tx/reply_rx"),
+        }
+    }
+}
+
+async fn probe_v4(
+    connect_opts: &ConnectOpts,
+    dst_addr: Ipv4Addr,
+    ident: u16,
+    seq_no: u16,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let request = Icmpv4Repr::EchoRequest { ident, seq_no, data };
+    let mut buf = vec![0u8; request.buffer_len()];
+    let mut packet = Icmpv4Packet::new_unchecked(&mut buf);
+    request.emit(&mut packet, &ChecksumCapabilities::default());
+
+    let expected_peer = IpAddr::V4(dst_addr);
+    let connect_opts = connect_opts.clone();
+    tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).map_err(require_net_raw)?;
+        apply_connect_opts(&socket, &connect_opts, Domain::IPV4)?;
+        socket.send_to(&buf, &std::net::SocketAddr::new(expected_peer, 0).into())?;
+
+        let deadline = Instant::now() + ECHO_REPLY_TIMEOUT;
+        recv_matching_echo_reply(&socket, expected_peer, ident, seq_no, deadline, parse_icmpv4_echo_reply)
+    })
+    .await
+    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?
+}
+
+async fn probe_v6(
+    connect_opts: &ConnectOpts,
+    dst_addr: Ipv6Addr,
+    ident: u16,
+    seq_no: u16,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    // ICMPv6 checksums are pseudo-header dependent; the kernel computes it
+    // for us on a raw ICMPv6 socket, so we only need to emit the header.
+    let request = Icmpv6Repr::EchoRequest { ident, seq_no, data };
+    let mut buf = vec![0u8; request.buffer_len()];
+    let mut packet = Icmpv6Packet::new_unchecked(&mut buf);
+    packet.set_msg_type(smoltcp::wire::Icmpv6Message::EchoRequest);
+    packet.set_msg_code(0);
+    packet.set_echo_ident(ident);
+    packet.set_echo_seq_no(seq_no);
+    packet.payload_mut().copy_from_slice(data);
+
+    let expected_peer = IpAddr::V6(dst_addr);
+    let connect_opts = connect_opts.clone();
+    tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6)).map_err(require_net_raw)?;
+        apply_connect_opts(&socket, &connect_opts, Domain::IPV6)?;
+        socket.send_to(&buf, &std::net::SocketAddr::new(expected_peer, 0).into())?;
+
+        let deadline = Instant::now() + ECHO_REPLY_TIMEOUT;
+        recv_matching_echo_reply(&socket, expected_peer, ident, seq_no, deadline, parse_icmpv6_echo_reply)
+    })
+    .await
+    .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?
+}
+
+/// Re-labels the `EPERM` that `Socket::new(.., Type::RAW, ..)` returns
+/// without `CAP_NET_RAW` (or root) with a message that says so, instead of
+/// leaving callers to guess why ICMP proxying silently never replies.
+fn require_net_raw(err: Error) -> Error {
+    if err.kind() == ErrorKind::PermissionDenied {
+        Error::new(
+            ErrorKind::PermissionDenied,
+            "opening a raw ICMP socket requires CAP_NET_RAW (or running as root)",
+        )
+    } else {
+        err
+    }
+}
+
+/// Steers the probe socket's egress the same way `connect_opts` steers
+/// proxied TCP/UDP connections, so pings measure reachability over the
+/// path the proxy actually uses rather than the kernel's default route.
+fn apply_connect_opts(socket: &Socket, connect_opts: &ConnectOpts, domain: Domain) -> Result<()> {
+    if let Some(addr) = connect_opts.bind_local_addr {
+        let matches_domain = matches!(
+            (domain, addr),
+            (Domain::IPV4, IpAddr::V4(_)) | (Domain::IPV6, IpAddr::V6(_))
+        );
+        if matches_domain {
+            socket.bind(&std::net::SocketAddr::new(addr, 0).into())?;
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+    if let Some(iface) = &connect_opts.bind_interface {
+        socket.bind_device(Some(iface.as_bytes()))?;
+    }
+
+    Ok(())
+}
+
+/// Blocks on `socket` until a reply from `expected_peer` carrying a matching
+/// `ident`/`seq_no` arrives, `deadline` passes, or a real I/O error occurs.
+/// A raw ICMP socket receives *every* ICMP datagram bound for this host, so
+/// replies to other concurrent probes (or unrelated echo traffic) must be
+/// filtered out rather than accepted as this probe's answer.
+fn recv_matching_echo_reply(
+    socket: &Socket,
+    expected_peer: IpAddr,
+    ident: u16,
+    seq_no: u16,
+    deadline: Instant,
+    parse: impl Fn(&[u8]) -> Option<(u16, u16, Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::new(ErrorKind::TimedOut, "ICMP echo probe timed out"));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let mut raw_buf = [MaybeUninit::uninit(); 4096];
+        let (n, peer) = socket.recv_from(&mut raw_buf)?;
+        if peer.as_socket().map(|addr| addr.ip()) != Some(expected_peer) {
+            continue;
+        }
+
+        let received: Vec<u8> = raw_buf[..n].iter().map(|b| unsafe { b.assume_init() }).collect();
+        match parse(&received) {
+            Some((reply_ident, reply_seq, reply_data)) if reply_ident == ident && reply_seq == seq_no => {
+                return Ok(reply_data);
+            }
+            _ => continue,
+        }
+    }
+}
+
+fn parse_icmpv4_echo_reply(raw: &[u8]) -> Option<(u16, u16, Vec<u8>)> {
+    // Skip the IPv4 header the kernel prepends to the raw-socket payload.
+    let ihl = ((raw.first().copied().unwrap_or(0x45) & 0x0f) as usize) * 4;
+    let icmp_payload = raw.get(ihl..)?;
+    let packet = Icmpv4Packet::new_checked(icmp_payload).ok()?;
+    match Icmpv4Repr::parse(&packet, &ChecksumCapabilities::default()) {
+        Ok(Icmpv4Repr::EchoReply { ident, seq_no, data }) => Some((ident, seq_no, data.to_vec())),
+        _ => None,
+    }
+}
+
+fn parse_icmpv6_echo_reply(raw: &[u8]) -> Option<(u16, u16, Vec<u8>)> {
+    let packet = Icmpv6Packet::new_checked(raw).ok()?;
+    if packet.msg_type() != smoltcp::wire::Icmpv6Message::EchoReply {
+        return None;
+    }
+    Some((packet.echo_ident(), packet.echo_seq_no(), packet.payload().to_vec()))
+}
+
+fn build_icmpv4_reply(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, ident: u16, seq_no: u16, data: &[u8]) -> Vec<u8> {
+    let icmp_repr = Icmpv4Repr::EchoReply { ident, seq_no, data };
+
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        next_header: smoltcp::wire::IpProtocol::Icmp,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+
+    let mut buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    let mut ip_packet = Ipv4Packet::new_unchecked(&mut buf);
+    ip_repr.emit(&mut ip_packet, &ChecksumCapabilities::default());
+
+    let mut icmp_packet = Icmpv4Packet::new_unchecked(ip_packet.payload_mut());
+    icmp_repr.emit(&mut icmp_packet, &ChecksumCapabilities::default());
+
+    buf
+}
+
+fn build_icmpv6_reply(src_addr: Ipv6Addr, dst_addr: Ipv6Addr, ident: u16, seq_no: u16, data: &[u8]) -> Vec<u8> {
+    let icmp_repr = Icmpv6Repr::EchoReply { ident, seq_no, data };
+
+    let ip_repr = Ipv6Repr {
+        src_addr,
+        dst_addr,
+        next_header: smoltcp::wire::IpProtocol::Icmpv6,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: 64,
+    };
+
+    let mut buf = vec![0u8; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    let mut ip_packet = Ipv6Packet::new_unchecked(&mut buf);
+    ip_repr.emit(&mut ip_packet);
+
+    let mut icmp_packet = Icmpv6Packet::new_unchecked(ip_packet.payload_mut());
+    icmp_repr.emit(
+        &IpAddress::Ipv6(src_addr),
+        &IpAddress::Ipv6(dst_addr),
+        &mut icmp_packet,
+        &ChecksumCapabilities::default(),
+    );
+
+    buf
+}
@@ -0,0 +1,101 @@
+//! Lightweight libpcap-format writer for capturing raw IP frames on the TUN device.
+//!
+//! Enabled via `TunInbound::new`'s `capture` option; mirrors the pcap-writer
+//! middleware pattern used by smoltcp's phy layer so a capture can be opened
+//! directly in Wireshark to diagnose why a flow isn't being proxied.
+
+use std::io::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// DLT_RAW: the captured frames are bare IP packets, no link-layer header.
+const LINKTYPE_RAW: u32 = 101;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+
+/// Optional caps on how much a single capture is allowed to record, so an
+/// unattended capture can't be left running until it fills the disk.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CaptureLimits {
+    pub max_bytes: Option<u64>,
+    pub max_packets: Option<u64>,
+}
+
+/// Async libpcap file writer used to tee TUN frames for offline inspection.
+pub struct PcapWriter {
+    file: Mutex<File>,
+    limits: CaptureLimits,
+    bytes_written: AtomicU64,
+    packets_written: AtomicU64,
+}
+
+impl PcapWriter {
+    /// Creates `path`, writes the pcap global header and returns a writer
+    /// ready to accept captured frames.
+    pub async fn create(path: impl AsRef<Path>, limits: CaptureLimits) -> Result<Self> {
+        let mut file = File::create(path).await?;
+        file.write_all(&Self::global_header()).await?;
+
+        Ok(PcapWriter {
+            file: Mutex::new(file),
+            limits,
+            bytes_written: AtomicU64::new(0),
+            packets_written: AtomicU64::new(0),
+        })
+    }
+
+    fn global_header() -> [u8; 24] {
+        let mut header = [0u8; 24];
+        header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes()); // version_major
+        header[6..8].copy_from_slice(&4u16.to_le_bytes()); // version_minor
+        // bytes 8..16 (thiszone, sigfigs) are left zeroed
+        header[16..20].copy_from_slice(&u32::MAX.to_le_bytes()); // snaplen
+        header[20..24].copy_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        header
+    }
+
+    /// Appends one captured frame as a pcap packet record, stamping it with
+    /// the current wall-clock time. Stops writing once a configured limit is
+    /// hit; failures are logged rather than propagated since a capture is a
+    /// best-effort debugging aid and must never take down the tunnel.
+    pub async fn write_packet(&self, data: &[u8]) {
+        if let Some(max) = self.limits.max_packets {
+            if self.packets_written.load(Ordering::Relaxed) >= max {
+                return;
+            }
+        }
+        if let Some(max) = self.limits.max_bytes {
+            if self.bytes_written.load(Ordering::Relaxed) >= max {
+                return;
+            }
+        }
+
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + data.len());
+        record.extend_from_slice(&(stamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&stamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes()); // incl_len
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes()); // orig_len
+        record.extend_from_slice(data);
+
+        let mut file = self.file.lock().await;
+        if let Err(err) = file.write_all(&record).await {
+            log::warn!("[TUN] failed to write pcap capture record, error: {}", err);
+            return;
+        }
+        drop(file);
+
+        self.bytes_written
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.packets_written.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,262 @@
+//! Automatic default-route capture and restore for "full-tunnel" TUN setups.
+//!
+//! When enabled, captures the system's current default gateway/interface,
+//! installs a route that sends all traffic through the TUN device while
+//! keeping a host route to the real proxy server via the original gateway,
+//! and restores the saved routing state on `Drop`/shutdown, so users get
+//! full-tunnel behavior without hand-writing `route add` commands per
+//! platform.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+
+use cfg_if::cfg_if;
+use tokio::process::Command;
+
+/// The default route observed before we took over routing, so it can be
+/// restored exactly as found.
+#[derive(Clone, Debug)]
+struct SavedDefaultRoute {
+    gateway: IpAddr,
+    interface: String,
+}
+
+/// Installs a full-tunnel default route through the TUN device on creation.
+///
+/// Callers must await [`RouteGuard::teardown`] on their own shutdown path;
+/// `Drop` only best-effort-spawns the same teardown onto the ambient
+/// runtime as a last resort, and a detached task like that is commonly
+/// never polled once the runtime starts shutting down, leaving the host's
+/// default route pointed at a now-gone TUN device.
+pub struct RouteGuard {
+    saved: SavedDefaultRoute,
+    server_addr: IpAddr,
+    torn_down: bool,
+}
+
+impl RouteGuard {
+    /// Captures the current default route, adds a host route to
+    /// `server_addr` via the original gateway (so the proxy connection
+    /// itself doesn't loop back through the tunnel), then points the
+    /// default route at `tun_name` (`tun_addr` is the TUN device's own
+    /// local address, needed on platforms where `route change` takes a
+    /// gateway address rather than an interface name).
+    pub async fn install(tun_name: &str, tun_addr: IpAddr, server_addr: IpAddr) -> Result<Self> {
+        let saved = capture_default_route().await?;
+
+        add_host_route(server_addr, &saved).await?;
+        if let Err(err) = replace_default_route(tun_name, tun_addr).await {
+            let _ = remove_host_route(server_addr).await;
+            return Err(err);
+        }
+
+        log::info!(
+            "[TUN] full-tunnel enabled, default route now via {}, proxy {} pinned via {} ({})",
+            tun_name,
+            server_addr,
+            saved.gateway,
+            saved.interface
+        );
+
+        Ok(RouteGuard {
+            saved,
+            server_addr,
+            torn_down: false,
+        })
+    }
+
+    /// Restores the saved default route and removes the host route. Safe to
+    /// call more than once; only the first call does any work.
+    pub async fn teardown(&mut self) {
+        if self.torn_down {
+            return;
+        }
+        self.torn_down = true;
+
+        if let Err(err) = restore_default_route(&self.saved).await {
+            log::warn!("[TUN] failed to restore default route, error: {}", err);
+        }
+        if let Err(err) = remove_host_route(self.server_addr).await {
+            log::warn!("[TUN] failed to remove proxy host route, error: {}", err);
+        }
+    }
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        if self.torn_down {
+            return;
+        }
+        self.torn_down = true;
+
+        // `Drop` isn't async; best-effort the teardown on the ambient runtime
+        // so a forgotten explicit `teardown().await` still restores routing.
+        let saved = self.saved.clone();
+        let server_addr = self.server_addr;
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Err(err) = restore_default_route(&saved).await {
+                    log::warn!("[TUN] failed to restore default route, error: {}", err);
+                }
+                if let Err(err) = remove_host_route(server_addr).await {
+                    log::warn!("[TUN] failed to remove proxy host route, error: {}", err);
+                }
+            });
+        }
+    }
+}
+
+async fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program).args(args).status().await?;
+    if !status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("`{} {}` exited with {}", program, args.join(" "), status),
+        ));
+    }
+    Ok(())
+}
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        async fn capture_default_route() -> Result<SavedDefaultRoute> {
+            let table = tokio::fs::read_to_string("/proc/net/route").await?;
+            for line in table.lines().skip(1) {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                // iface destination gateway flags ... (see route(8) for the /proc/net/route layout)
+                if fields.len() < 3 || fields[1] != "00000000" {
+                    continue;
+                }
+                let gateway = parse_hex_le_ipv4(fields[2])?;
+                return Ok(SavedDefaultRoute {
+                    gateway: IpAddr::V4(gateway),
+                    interface: fields[0].to_owned(),
+                });
+            }
+            Err(Error::new(ErrorKind::NotFound, "no default route found in /proc/net/route"))
+        }
+
+        fn parse_hex_le_ipv4(field: &str) -> Result<std::net::Ipv4Addr> {
+            let raw = u32::from_str_radix(field, 16)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "malformed /proc/net/route gateway field"))?;
+            Ok(std::net::Ipv4Addr::from(raw.to_le_bytes()))
+        }
+
+        async fn add_host_route(server_addr: IpAddr, saved: &SavedDefaultRoute) -> Result<()> {
+            run("ip", &["route", "add", &server_addr.to_string(), "via", &saved.gateway.to_string(), "dev", &saved.interface]).await
+        }
+
+        async fn remove_host_route(server_addr: IpAddr) -> Result<()> {
+            run("ip", &["route", "del", &server_addr.to_string()]).await
+        }
+
+        async fn replace_default_route(tun_name: &str, _tun_addr: IpAddr) -> Result<()> {
+            run("ip", &["route", "replace", "default", "dev", tun_name]).await
+        }
+
+        async fn restore_default_route(saved: &SavedDefaultRoute) -> Result<()> {
+            run("ip", &["route", "replace", "default", "via", &saved.gateway.to_string(), "dev", &saved.interface]).await
+        }
+    } else if #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))] {
+        async fn capture_default_route() -> Result<SavedDefaultRoute> {
+            let output = Command::new("route").args(["-n", "get", "default"]).output().await?;
+            let text = String::from_utf8_lossy(&output.stdout);
+
+            let mut gateway = None;
+            let mut interface = None;
+            for line in text.lines() {
+                let line = line.trim();
+                if let Some(value) = line.strip_prefix("gateway:") {
+                    gateway = value.trim().parse::<IpAddr>().ok();
+                } else if let Some(value) = line.strip_prefix("interface:") {
+                    interface = Some(value.trim().to_owned());
+                }
+            }
+
+            match (gateway, interface) {
+                (Some(gateway), Some(interface)) => Ok(SavedDefaultRoute { gateway, interface }),
+                _ => Err(Error::new(ErrorKind::NotFound, "could not parse default route from `route -n get default`")),
+            }
+        }
+
+        async fn add_host_route(server_addr: IpAddr, saved: &SavedDefaultRoute) -> Result<()> {
+            run("route", &["add", "-host", &server_addr.to_string(), &saved.gateway.to_string()]).await
+        }
+
+        async fn remove_host_route(server_addr: IpAddr) -> Result<()> {
+            run("route", &["delete", "-host", &server_addr.to_string()]).await
+        }
+
+        async fn replace_default_route(tun_name: &str, _tun_addr: IpAddr) -> Result<()> {
+            // Point-to-point TUN interfaces have no gateway of their own;
+            // `-interface` tells `route` to route via the interface directly.
+            run("route", &["change", "default", "-interface", tun_name]).await
+        }
+
+        async fn restore_default_route(saved: &SavedDefaultRoute) -> Result<()> {
+            // `route change destination gateway`: passing both a gateway
+            // address and `-interface` is invalid here, `-interface` is only
+            // for gateway-less (point-to-point) routes.
+            run("route", &["change", "default", &saved.gateway.to_string()]).await
+        }
+    } else if #[cfg(target_os = "windows")] {
+        async fn capture_default_route() -> Result<SavedDefaultRoute> {
+            // Coarse equivalent of the IP Helper API's `GetBestRoute`/
+            // `GetIpForwardTable` lookup, shelling out to `route print` for
+            // the "0.0.0.0  0.0.0.0  <gateway>  <interface>  <metric>" row.
+            let output = Command::new("route").args(["print", "-4", "0.0.0.0"]).output().await?;
+            let text = String::from_utf8_lossy(&output.stdout);
+
+            for line in text.lines() {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() >= 4 && fields[0] == "0.0.0.0" && fields[1] == "0.0.0.0" {
+                    let gateway = fields[2].parse::<IpAddr>()
+                        .map_err(|_| Error::new(ErrorKind::InvalidData, "malformed `route print` gateway field"))?;
+                    return Ok(SavedDefaultRoute { gateway, interface: fields[3].to_owned() });
+                }
+            }
+            Err(Error::new(ErrorKind::NotFound, "no default route found in `route print` output"))
+        }
+
+        async fn add_host_route(server_addr: IpAddr, saved: &SavedDefaultRoute) -> Result<()> {
+            run("route", &["add", &server_addr.to_string(), "mask", "255.255.255.255", &saved.gateway.to_string()]).await
+        }
+
+        async fn remove_host_route(server_addr: IpAddr) -> Result<()> {
+            run("route", &["delete", &server_addr.to_string()]).await
+        }
+
+        async fn replace_default_route(_tun_name: &str, tun_addr: IpAddr) -> Result<()> {
+            // `route change destination mask netmask gateway`: the last
+            // positional argument is a gateway *address*, not an interface
+            // name, so the TUN device's own local address is passed here
+            // (valid for a point-to-point interface, where the device's
+            // address also serves as the next hop).
+            run("route", &["change", "0.0.0.0", "mask", "0.0.0.0", &tun_addr.to_string()]).await
+        }
+
+        async fn restore_default_route(saved: &SavedDefaultRoute) -> Result<()> {
+            run("route", &["change", "0.0.0.0", "mask", "0.0.0.0", &saved.gateway.to_string()]).await
+        }
+    } else {
+        async fn capture_default_route() -> Result<SavedDefaultRoute> {
+            Err(Error::new(ErrorKind::Unsupported, "full-tunnel routing is not supported on this platform"))
+        }
+
+        async fn add_host_route(_server_addr: IpAddr, _saved: &SavedDefaultRoute) -> Result<()> {
+            Err(Error::new(ErrorKind::Unsupported, "full-tunnel routing is not supported on this platform"))
+        }
+
+        async fn remove_host_route(_server_addr: IpAddr) -> Result<()> {
+            Ok(())
+        }
+
+        async fn replace_default_route(_tun_name: &str, _tun_addr: IpAddr) -> Result<()> {
+            Err(Error::new(ErrorKind::Unsupported, "full-tunnel routing is not supported on this platform"))
+        }
+
+        async fn restore_default_route(_saved: &SavedDefaultRoute) -> Result<()> {
+            Ok(())
+        }
+    }
+}